@@ -10,9 +10,12 @@
 #![doc(html_root_url = "https://docs.rs/charset/0.1.0")]
 
 //! `charset` is a wrapper around [`encoding_rs`][1] that provides
-//! (non-streaming) decoding for character encodings that occur in _email_ by
-//! providing decoding for [UTF-7][2] in addition to the encodings defined by
-//! the [Encoding Standard][3] (and provided by `encoding_rs`).
+//! decoding for character encodings that occur in _email_ by providing
+//! decoding for [UTF-7][2] in addition to the encodings defined by the
+//! [Encoding Standard][3] (and provided by `encoding_rs`). Besides the
+//! one-shot `decode*` methods on [`Charset`], an incremental [`Decoder`]
+//! and a [`DecodeReader`] adapter over `std::io::Read` are available for
+//! callers that don't want to buffer an entire message in memory.
 //!
 //! _Note:_ Do _not_ use this crate for consuming _Web_ content. For security
 //! reasons, consumers of Web content are [_prohibited_][4] from supporting
@@ -32,13 +35,6 @@
 //!  * JavaMail may use non-standard labels for legacy encodings such that
 //!    the labels aren't recognized by this crate even if the encodings
 //!    themselves would be supported.
-//!  * Some ancient Usenet posting in Chinese may not be decodable, because
-//!    this crate does not support HZ.
-//!  * Some emails sent in Chinese by Sun's email client for CDE on Solaris
-//!    around the turn of the millennium may not decodable, because this
-//!    crate does not support ISO-2022-CN.
-//!  * Some emails sent in Korean by IBM/Lotus Notes may not be decodable,
-//!    because this crate does not support ISO-2022-KR.
 //!
 //! This crate intentionally does not support encoding content into legacy
 //! encodings. When sending email, _always_ use UTF-8. This is, just call
@@ -54,18 +50,45 @@ extern crate base64;
 extern crate encoding_rs;
 
 use encoding_rs::CoderResult;
+use encoding_rs::DecoderResult;
 use encoding_rs::Encoding;
+use encoding_rs::EUC_KR;
 use encoding_rs::GB18030;
 use encoding_rs::GBK;
 use encoding_rs::UTF_16BE;
 
 use std::borrow::Cow;
+use std::io;
+use std::io::Read;
 
 /// The UTF-7 encoding.
 pub const UTF_7: Charset = Charset {
     variant: VariantCharset::Utf7,
 };
 
+/// The IMAP modified UTF-7 encoding ([RFC 3501][1] section 5.1.3), used for
+/// IMAP mailbox names rather than for email bodies.
+///
+/// [1]: https://tools.ietf.org/html/rfc3501#section-5.1.3
+pub const MODIFIED_UTF_7: Charset = Charset {
+    variant: VariantCharset::ModifiedUtf7,
+};
+
+/// The HZ-GB-2312 encoding.
+pub const HZ_GB_2312: Charset = Charset {
+    variant: VariantCharset::Hz,
+};
+
+/// The ISO-2022-KR encoding.
+pub const ISO_2022_KR: Charset = Charset {
+    variant: VariantCharset::Iso2022Kr,
+};
+
+/// The ISO-2022-CN encoding.
+pub const ISO_2022_CN: Charset = Charset {
+    variant: VariantCharset::Iso2022Cn,
+};
+
 /// A character encoding suitable for decoding _email_.
 ///
 /// This is either an encoding as defined in the [Encoding Standard][1]
@@ -92,6 +115,25 @@ pub struct Charset {
     variant: VariantCharset,
 }
 
+/// How a `decode_*_with_mode` method should handle a malformed byte
+/// sequence, in the style of the `DecoderTrap` advanced interface for error
+/// detection and recovery from the (now largely superseded) `encoding`
+/// crate.
+#[derive(Copy, Clone, Debug)]
+pub enum ErrorMode {
+    /// Replace each malformed sequence with the REPLACEMENT CHARACTER. This
+    /// is the only behavior available from `decode()`,
+    /// `decode_with_bom_removal()`, and `decode_without_bom_handling()`.
+    Replace,
+    /// Stop at the first malformed sequence instead of producing output,
+    /// and report its byte offset into the input as the `Err` value of the
+    /// `decode_*_with_mode` method.
+    Strict,
+    /// Replace each malformed sequence with whatever the given function
+    /// returns for it, e.g. a numeric character reference.
+    Custom(for<'a> fn(&'a [u8]) -> Cow<'a, str>),
+}
+
 impl Charset {
     /// Implements the
     /// [_get an encoding_](https://encoding.spec.whatwg.org/#concept-encoding-get)
@@ -116,10 +158,19 @@ impl Charset {
     /// on it.)
     #[inline]
     pub fn for_label(label: &[u8]) -> Option<Charset> {
-        if let Some(encoding) = Encoding::for_label(label) {
-            Some(Charset::for_encoding(encoding))
-        } else if is_utf7_label(label) {
+        // UTF-7, HZ-GB-2312, ISO-2022-KR and ISO-2022-CN are intercepted
+        // before consulting `encoding_rs`, because the Encoding Standard
+        // maps (some of) their labels to the REPLACEMENT encoding.
+        if is_utf7_label(label) {
             Some(UTF_7)
+        } else if is_hz_label(label) {
+            Some(HZ_GB_2312)
+        } else if is_iso_2022_kr_label(label) {
+            Some(ISO_2022_KR)
+        } else if is_iso_2022_cn_label(label) {
+            Some(ISO_2022_CN)
+        } else if let Some(encoding) = Encoding::for_label(label) {
+            Some(Charset::for_encoding(encoding))
         } else {
             None
         }
@@ -142,10 +193,16 @@ impl Charset {
     /// useful for `text/plain` email, though.
     #[inline]
     pub fn for_label_no_replacement(label: &[u8]) -> Option<Charset> {
-        if let Some(encoding) = Encoding::for_label_no_replacement(label) {
-            Some(Charset::for_encoding(encoding))
-        } else if is_utf7_label(label) {
+        if is_utf7_label(label) {
             Some(UTF_7)
+        } else if is_hz_label(label) {
+            Some(HZ_GB_2312)
+        } else if is_iso_2022_kr_label(label) {
+            Some(ISO_2022_KR)
+        } else if is_iso_2022_cn_label(label) {
+            Some(ISO_2022_CN)
+        } else if let Some(encoding) = Encoding::for_label_no_replacement(label) {
+            Some(Charset::for_encoding(encoding))
         } else {
             None
         }
@@ -190,6 +247,10 @@ impl Charset {
         match self.variant {
             VariantCharset::Encoding(encoding) => encoding.name(),
             VariantCharset::Utf7 => "UTF-7",
+            VariantCharset::Hz => "HZ-GB-2312",
+            VariantCharset::Iso2022Kr => "ISO-2022-KR",
+            VariantCharset::Iso2022Cn => "ISO-2022-CN",
+            VariantCharset::ModifiedUtf7 => "MODIFIED-UTF-7",
         }
     }
 
@@ -200,6 +261,10 @@ impl Charset {
         match self.variant {
             VariantCharset::Encoding(encoding) => encoding.is_ascii_compatible(),
             VariantCharset::Utf7 => false,
+            VariantCharset::Hz => false,
+            VariantCharset::Iso2022Kr => false,
+            VariantCharset::Iso2022Cn => false,
+            VariantCharset::ModifiedUtf7 => false,
         }
     }
 
@@ -259,6 +324,10 @@ impl Charset {
         match self.variant {
             VariantCharset::Encoding(encoding) => encoding.decode_with_bom_removal(bytes),
             VariantCharset::Utf7 => decode_utf7(bytes),
+            VariantCharset::Hz => decode_hz(bytes),
+            VariantCharset::Iso2022Kr => decode_iso2022kr(bytes),
+            VariantCharset::Iso2022Cn => decode_iso2022cn(bytes),
+            VariantCharset::ModifiedUtf7 => decode_modified_utf7(bytes),
         }
     }
 
@@ -287,7 +356,518 @@ impl Charset {
         match self.variant {
             VariantCharset::Encoding(encoding) => encoding.decode_without_bom_handling(bytes),
             VariantCharset::Utf7 => decode_utf7(bytes),
+            VariantCharset::Hz => decode_hz(bytes),
+            VariantCharset::Iso2022Kr => decode_iso2022kr(bytes),
+            VariantCharset::Iso2022Cn => decode_iso2022cn(bytes),
+            VariantCharset::ModifiedUtf7 => decode_modified_utf7(bytes),
+        }
+    }
+
+    /// Like `decode()`, but lets the caller pick how malformed sequences are
+    /// handled via `mode` instead of always substituting the REPLACEMENT
+    /// CHARACTER.
+    ///
+    /// Returns `Err(offset)` when `mode` is `ErrorMode::Strict` and a
+    /// malformed sequence starts at byte `offset` of `bytes`. Otherwise,
+    /// behaves like `decode()`, with malformed sequences handled per `mode`
+    /// and the third item of the returned tuple still indicating whether
+    /// there were any.
+    ///
+    /// _Note:_ `mode` is currently only honored for encodings backed by
+    /// `encoding_rs` and for `UTF_7`; the other charsets defined directly by
+    /// this crate (`HZ_GB_2312`, `ISO_2022_KR`, `ISO_2022_CN`, and
+    /// `MODIFIED_UTF_7`) always use `ErrorMode::Replace` semantics regardless
+    /// of `mode`.
+    ///
+    /// # Panics
+    ///
+    /// If the size calculation for a heap-allocated backing buffer overflows
+    /// `usize`.
+    #[inline]
+    pub fn decode_with_mode<'a>(
+        self,
+        bytes: &'a [u8],
+        mode: ErrorMode,
+    ) -> Result<(Cow<'a, str>, Charset, bool), usize> {
+        let (charset, bom_length) = match Charset::for_bom(bytes) {
+            Some((charset, bom_length)) => (charset, bom_length),
+            None => (self, 0),
+        };
+        let (cow, had_errors) = charset
+            .decode_without_bom_handling_with_mode(&bytes[bom_length..], mode)
+            .map_err(|offset| offset + bom_length)?;
+        Ok((cow, charset, had_errors))
+    }
+
+    /// Like `decode_with_bom_removal()`, but with configurable handling of
+    /// malformed sequences. See `decode_with_mode()` for the semantics of
+    /// `mode` and of the returned `Result`.
+    #[inline]
+    pub fn decode_with_bom_removal_with_mode<'a>(
+        self,
+        bytes: &'a [u8],
+        mode: ErrorMode,
+    ) -> Result<(Cow<'a, str>, bool), usize> {
+        match self.variant {
+            VariantCharset::Encoding(encoding) => {
+                decode_encoding_with_mode(encoding, bytes, mode, true)
+            }
+            VariantCharset::Utf7 => decode_utf7_with_mode(bytes, mode),
+            _ => Ok(self.decode_with_bom_removal(bytes)),
+        }
+    }
+
+    /// Like `decode_without_bom_handling()`, but with configurable handling
+    /// of malformed sequences. See `decode_with_mode()` for the semantics of
+    /// `mode` and of the returned `Result`.
+    #[inline]
+    pub fn decode_without_bom_handling_with_mode<'a>(
+        self,
+        bytes: &'a [u8],
+        mode: ErrorMode,
+    ) -> Result<(Cow<'a, str>, bool), usize> {
+        match self.variant {
+            VariantCharset::Encoding(encoding) => {
+                decode_encoding_with_mode(encoding, bytes, mode, false)
+            }
+            VariantCharset::Utf7 => decode_utf7_with_mode(bytes, mode),
+            _ => Ok(self.decode_without_bom_handling(bytes)),
+        }
+    }
+
+    /// Instantiates an incremental decoder for this character encoding,
+    /// for use when the input arrives in chunks instead of as a single
+    /// buffer (e.g. a large MIME part read off a `std::io::Read`).
+    ///
+    /// This mirrors [`encoding_rs::Encoding::new_decoder_without_bom_handling`][1];
+    /// see [`Decoder`] for the incremental API. As the name implies, the
+    /// returned decoder does not perform BOM sniffing or removal.
+    ///
+    /// [1]: https://docs.rs/encoding_rs/latest/encoding_rs/struct.Encoding.html#method.new_decoder_without_bom_handling
+    #[inline]
+    pub fn new_decoder_without_bom_handling(self) -> Decoder {
+        let variant = match self.variant {
+            VariantCharset::Encoding(encoding) => {
+                VariantDecoder::Encoding(encoding.new_decoder_without_bom_handling())
+            }
+            VariantCharset::Utf7 => VariantDecoder::Utf7(Utf7Decoder::new()),
+            VariantCharset::Hz => VariantDecoder::Buffered(BufferedDecoder::new(decode_hz)),
+            VariantCharset::Iso2022Kr => {
+                VariantDecoder::Buffered(BufferedDecoder::new(decode_iso2022kr))
+            }
+            VariantCharset::Iso2022Cn => {
+                VariantDecoder::Buffered(BufferedDecoder::new(decode_iso2022cn))
+            }
+            VariantCharset::ModifiedUtf7 => {
+                VariantDecoder::Buffered(BufferedDecoder::new(decode_modified_utf7))
+            }
+        };
+        Decoder { variant }
+    }
+}
+
+/// An incremental, push-based decoder, obtained from
+/// [`Charset::new_decoder_without_bom_handling`].
+///
+/// This mirrors the shape of [`encoding_rs::Decoder`][1]: feed it successive
+/// byte slices of the input via [`decode_to_string`][Decoder::decode_to_string],
+/// setting `last` to `true` on the final slice so that a trailing
+/// incomplete sequence is flushed (as the REPLACEMENT CHARACTER) instead of
+/// held forever.
+///
+/// Unlike `Charset`, `Decoder` carries state between calls and therefore
+/// isn't `Copy`.
+///
+/// For `MODIFIED_UTF_7`, `HZ_GB_2312`, `ISO_2022_KR`, and `ISO_2022_CN`,
+/// the decoder buffers all input internally and writes nothing to `dst`
+/// until the call with `last` set to `true`.
+///
+/// [1]: https://docs.rs/encoding_rs/latest/encoding_rs/struct.Decoder.html
+pub struct Decoder {
+    variant: VariantDecoder,
+}
+
+impl Decoder {
+    /// Incrementally decodes `src` into `dst`, appending to whatever `dst`
+    /// already contains.
+    ///
+    /// `last` must be `true` on the call that provides the final bytes of
+    /// the stream; this lets the decoder flush a trailing incomplete
+    /// sequence as the REPLACEMENT CHARACTER instead of waiting for bytes
+    /// that will never arrive.
+    ///
+    /// Returns the same triple as `encoding_rs::Decoder::decode_to_string`:
+    /// whether the call consumed all of `src` or stopped because `dst`
+    /// needs more capacity, how many bytes of `src` were consumed, and
+    /// whether malformed sequences were replaced with the REPLACEMENT
+    /// CHARACTER.
+    #[inline]
+    pub fn decode_to_string(
+        &mut self,
+        src: &[u8],
+        dst: &mut String,
+        last: bool,
+    ) -> (CoderResult, usize, bool) {
+        match &mut self.variant {
+            VariantDecoder::Encoding(decoder) => decoder.decode_to_string(src, dst, last),
+            VariantDecoder::Utf7(decoder) => decoder.decode_to_string(src, dst, last),
+            VariantDecoder::Buffered(decoder) => decoder.decode_to_string(src, dst, last),
+        }
+    }
+
+    // How much spare capacity `dst` needs for a `decode_to_string` call that
+    // consumes `byte_length` more input to be guaranteed not to return
+    // `CoderResult::OutputFull`. `Utf7` and `Buffered` always write through
+    // `String::push`/`push_str`, which grow the string themselves, so only
+    // the `Encoding` variant can actually report `OutputFull`.
+    fn max_utf8_buffer_length(&self, byte_length: usize) -> usize {
+        match &self.variant {
+            VariantDecoder::Encoding(decoder) => {
+                decoder.max_utf8_buffer_length(byte_length).unwrap()
+            }
+            VariantDecoder::Utf7(_) | VariantDecoder::Buffered(_) => byte_length,
+        }
+    }
+}
+
+enum VariantDecoder {
+    Encoding(encoding_rs::Decoder),
+    Utf7(Utf7Decoder),
+    Buffered(BufferedDecoder),
+}
+
+// A one-shot `decode_*` function, as found on the non-streaming `Charset`
+// methods. Used to back `Decoder` for the charsets that don't (yet) have a
+// genuinely incremental implementation: the whole input is buffered and
+// only decoded once `last` is true.
+type DecodeFn = for<'a> fn(&'a [u8]) -> (Cow<'a, str>, bool);
+
+struct BufferedDecoder {
+    buffer: Vec<u8>,
+    decode: DecodeFn,
+}
+
+impl BufferedDecoder {
+    fn new(decode: DecodeFn) -> Self {
+        BufferedDecoder {
+            buffer: Vec::new(),
+            decode,
+        }
+    }
+
+    fn decode_to_string(
+        &mut self,
+        src: &[u8],
+        dst: &mut String,
+        last: bool,
+    ) -> (CoderResult, usize, bool) {
+        self.buffer.extend_from_slice(src);
+        if !last {
+            return (CoderResult::InputEmpty, src.len(), false);
+        }
+        let (cow, had_errors) = (self.decode)(&self.buffer);
+        dst.push_str(&cow);
+        self.buffer.clear();
+        (CoderResult::InputEmpty, src.len(), had_errors)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Utf7Mode {
+    Ascii,
+    Base64,
+}
+
+// A genuinely incremental UTF-7 decoder. ASCII-mode bytes are passed
+// through directly; base64-mode bytes are accumulated four at a time (one
+// base64 group), decoded to raw bytes, and fed to a `UTF_16BE` decoder that
+// lives for the duration of a single `+...-` run and is carried across
+// `decode_to_string` calls along with any left-over base64 characters that
+// didn't complete a group yet.
+struct Utf7Decoder {
+    mode: Utf7Mode,
+    // Set after consuming a `+` in ASCII mode, while we're still waiting to
+    // see whether the next byte is `-` (literal `+`) or the start of a
+    // base64 run.
+    pending_shift: bool,
+    base64_pending: [u8; 4],
+    base64_pending_len: u8,
+    utf16: Option<encoding_rs::Decoder>,
+}
+
+impl Utf7Decoder {
+    fn new() -> Self {
+        Utf7Decoder {
+            mode: Utf7Mode::Ascii,
+            pending_shift: false,
+            base64_pending: [0u8; 4],
+            base64_pending_len: 0,
+            utf16: None,
+        }
+    }
+
+    fn decode_to_string(
+        &mut self,
+        src: &[u8],
+        dst: &mut String,
+        last: bool,
+    ) -> (CoderResult, usize, bool) {
+        let mut had_errors = false;
+        let mut consumed = 0usize;
+        while consumed < src.len() {
+            let byte = src[consumed];
+            match self.mode {
+                Utf7Mode::Ascii => {
+                    if self.pending_shift {
+                        self.pending_shift = false;
+                        if byte == b'-' {
+                            dst.push('+');
+                            consumed += 1;
+                        } else if utf7_base64_up_to(&src[consumed..consumed + 1]) == 0 {
+                            // Plus sign didn't start a base64 run and also
+                            // wasn't followed by a minus. This byte isn't
+                            // consumed and is reprocessed as ASCII below.
+                            had_errors = true;
+                            dst.push('\u{FFFD}');
+                        } else {
+                            self.mode = Utf7Mode::Base64;
+                            self.utf16 = Some(UTF_16BE.new_decoder_without_bom_handling());
+                            // Don't consume; this byte is the first byte of
+                            // the base64 run and is reprocessed below.
+                        }
+                    } else if byte == b'+' {
+                        self.pending_shift = true;
+                        consumed += 1;
+                    } else if byte < 0x80 {
+                        dst.push(byte as char);
+                        consumed += 1;
+                    } else {
+                        had_errors = true;
+                        dst.push('\u{FFFD}');
+                        consumed += 1;
+                    }
+                }
+                Utf7Mode::Base64 => match byte {
+                    b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'+' | b'/' => {
+                        self.base64_pending[self.base64_pending_len as usize] = byte;
+                        self.base64_pending_len += 1;
+                        consumed += 1;
+                        if self.base64_pending_len == 4 {
+                            self.flush_base64_group(dst, &mut had_errors);
+                        }
+                    }
+                    _ => {
+                        self.flush_base64_tail(dst, &mut had_errors);
+                        self.utf16 = None;
+                        self.mode = Utf7Mode::Ascii;
+                        if byte == b'-' {
+                            consumed += 1;
+                        }
+                        // Otherwise the byte wasn't a minus sign, so it's
+                        // reprocessed as ASCII (or rejected as non-ASCII).
+                    }
+                },
+            }
+        }
+        if last {
+            match self.mode {
+                Utf7Mode::Ascii => {
+                    if self.pending_shift {
+                        // A lone trailing `+` with no input left to tell us
+                        // whether it was a literal `+` or a shift.
+                        had_errors = true;
+                        dst.push('\u{FFFD}');
+                        self.pending_shift = false;
+                    }
+                }
+                Utf7Mode::Base64 => {
+                    self.flush_base64_tail(dst, &mut had_errors);
+                    self.utf16 = None;
+                    self.mode = Utf7Mode::Ascii;
+                }
+            }
+        }
+        (CoderResult::InputEmpty, consumed, had_errors)
+    }
+
+    fn flush_base64_group(&mut self, dst: &mut String, had_errors: &mut bool) {
+        let mut buf = [0u8; 3];
+        match base64::decode_config_slice(
+            &self.base64_pending[..4],
+            base64::STANDARD_NO_PAD,
+            &mut buf[..],
+        ) {
+            Ok(len) => self.feed_utf16(&buf[..len], dst, had_errors, false),
+            Err(_) => {
+                *had_errors = true;
+                dst.push_str("\u{FFFD}");
+            }
+        }
+        self.base64_pending_len = 0;
+    }
+
+    fn flush_base64_tail(&mut self, dst: &mut String, had_errors: &mut bool) {
+        let len = self.base64_pending_len as usize;
+        let mut buf = [0u8; 3];
+        let decoded_len = if len >= 2 {
+            match base64::decode_config_slice(
+                &self.base64_pending[..len],
+                base64::STANDARD_NO_PAD,
+                &mut buf[..],
+            ) {
+                Ok(n) => n,
+                Err(_) => {
+                    *had_errors = true;
+                    dst.push_str("\u{FFFD}");
+                    0
+                }
+            }
+        } else {
+            if len == 1 {
+                *had_errors = true;
+                dst.push_str("\u{FFFD}");
+            }
+            0
+        };
+        self.feed_utf16(&buf[..decoded_len], dst, had_errors, true);
+        self.base64_pending_len = 0;
+    }
+
+    fn feed_utf16(&mut self, bytes: &[u8], dst: &mut String, had_errors: &mut bool, last: bool) {
+        let decoder = match self.utf16.as_mut() {
+            Some(decoder) => decoder,
+            None => return,
+        };
+        let mut total_read = 0;
+        loop {
+            let (result, read, err) = decoder.decode_to_string(&bytes[total_read..], dst, last);
+            total_read += read;
+            *had_errors |= err;
+            match result {
+                CoderResult::InputEmpty => return,
+                CoderResult::OutputFull => {
+                    let left = bytes.len() - total_read;
+                    let needed = decoder.max_utf8_buffer_length(left).unwrap();
+                    dst.reserve(needed);
+                }
+            }
+        }
+    }
+}
+
+// Large enough to amortize the cost of a `read()` call on the wrapped
+// reader without holding an unreasonable amount of memory.
+const DECODE_READER_BUF_SIZE: usize = 8192;
+
+/// A [`Read`] adapter that transcodes bytes in some [`Charset`] to
+/// UTF-8 on the fly, without requiring the caller to buffer the whole input.
+///
+/// This is built on top of [`Decoder`], so it can transcode UTF-7 (and
+/// Modified UTF-7, HZ-GB-2312, ISO-2022-KR, and ISO-2022-CN) in addition to
+/// every encoding `encoding_rs` supports. Malformed sequences are replaced
+/// with the REPLACEMENT CHARACTER, matching the non-streaming `decode*`
+/// methods on `Charset`, with one exception: a UTF-7 stream that ends in a
+/// lone `+` yields a REPLACEMENT CHARACTER here, while the non-streaming
+/// methods drop that `+` without reporting an error.
+///
+/// For Modified UTF-7, HZ-GB-2312, ISO-2022-KR, and ISO-2022-CN, the
+/// underlying [`Decoder`] holds the whole input in memory and only produces
+/// output once the end of the stream has been reached.
+///
+/// If constructed with BOM sniffing enabled, `DecodeReader` defers picking a
+/// decoder until it has buffered enough of the start of the stream to call
+/// [`Charset::for_bom`]; if no BOM is found (or the stream ends before one
+/// could be), it falls back to the `Charset` given to [`DecodeReader::new`].
+pub struct DecodeReader<R> {
+    inner: R,
+    decoder: Option<Decoder>,
+    fallback: Charset,
+    sniff_buf: Vec<u8>,
+    raw_buf: Box<[u8]>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> DecodeReader<R> {
+    /// Creates a new `DecodeReader` that decodes `inner` as `fallback`.
+    ///
+    /// If `honor_bom` is `true`, a byte order mark at the start of the
+    /// stream overrides `fallback`, per [`Charset::for_bom`].
+    pub fn new(inner: R, fallback: Charset, honor_bom: bool) -> DecodeReader<R> {
+        DecodeReader {
+            inner,
+            decoder: if honor_bom {
+                None
+            } else {
+                Some(fallback.new_decoder_without_bom_handling())
+            },
+            fallback,
+            sniff_buf: Vec::new(),
+            raw_buf: vec![0u8; DECODE_READER_BUF_SIZE].into_boxed_slice(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            eof: false,
+        }
+    }
+
+    // Reads from `inner` until either a decoder has been committed to (by
+    // finding or ruling out a BOM) or `inner` is exhausted. Returns the
+    // bytes that should be fed to the (now-guaranteed-`Some`) decoder, and
+    // whether `inner` has reached EOF.
+    fn fill_decoder(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        loop {
+            let n = self.inner.read(&mut self.raw_buf)?;
+            let last = n == 0;
+            if self.decoder.is_some() {
+                return Ok((self.raw_buf[..n].to_vec(), last));
+            }
+            self.sniff_buf.extend_from_slice(&self.raw_buf[..n]);
+            if self.sniff_buf.len() >= 3 || last {
+                let rest = if let Some((charset, bom_len)) = Charset::for_bom(&self.sniff_buf) {
+                    self.decoder = Some(charset.new_decoder_without_bom_handling());
+                    self.sniff_buf.split_off(bom_len)
+                } else {
+                    self.decoder = Some(self.fallback.new_decoder_without_bom_handling());
+                    std::mem::take(&mut self.sniff_buf)
+                };
+                return Ok((rest, last));
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for DecodeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending_pos >= self.pending.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            let (src, last) = self.fill_decoder()?;
+            self.eof = last;
+            let decoder = self.decoder.as_mut().unwrap();
+            let mut out = String::with_capacity(decoder.max_utf8_buffer_length(src.len()));
+            let mut total_read = 0;
+            loop {
+                let (result, read, _) =
+                    decoder.decode_to_string(&src[total_read..], &mut out, last);
+                total_read += read;
+                match result {
+                    CoderResult::InputEmpty => break,
+                    CoderResult::OutputFull => {
+                        let left = src.len() - total_read;
+                        out.reserve(decoder.max_utf8_buffer_length(left));
+                    }
+                }
+            }
+            self.pending = out.into_bytes();
+            self.pending_pos = 0;
         }
+        let available = &self.pending[self.pending_pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pending_pos += n;
+        Ok(n)
     }
 }
 
@@ -343,6 +923,91 @@ fn is_utf7_label(label: &[u8]) -> bool {
     }
 }
 
+#[inline(never)]
+fn is_hz_label(label: &[u8]) -> bool {
+    let mut iter = label.into_iter();
+    // before
+    loop {
+        match iter.next() {
+            None => {
+                return false;
+            }
+            Some(&byte) => match byte {
+                0x09u8 | 0x0Au8 | 0x0Cu8 | 0x0Du8 | 0x20u8 => {
+                    continue;
+                }
+                b'h' | b'H' => {
+                    break;
+                }
+                _ => {
+                    return false;
+                }
+            },
+        }
+    }
+    // inside
+    let tail = iter.as_slice();
+    if tail.is_empty() || (tail[0] | 0x20) != b'z' {
+        return false;
+    }
+    let tail = &tail[1..];
+    let after = if tail.len() >= 8
+        && tail[0] == b'-'
+        && (tail[1] | 0x20) == b'g'
+        && (tail[2] | 0x20) == b'b'
+        && tail[3] == b'-'
+        && tail[4] == b'2'
+        && tail[5] == b'3'
+        && tail[6] == b'1'
+        && tail[7] == b'2'
+    {
+        &tail[8..]
+    } else {
+        tail
+    };
+    iter = after.into_iter();
+    // after
+    loop {
+        match iter.next() {
+            None => {
+                return true;
+            }
+            Some(&byte) => match byte {
+                0x09u8 | 0x0Au8 | 0x0Cu8 | 0x0Du8 | 0x20u8 => {
+                    continue;
+                }
+                _ => {
+                    return false;
+                }
+            },
+        }
+    }
+}
+
+#[inline]
+fn trim_label(label: &[u8]) -> &[u8] {
+    fn is_space(byte: u8) -> bool {
+        byte == 0x09u8 || byte == 0x0Au8 || byte == 0x0Cu8 || byte == 0x0Du8 || byte == 0x20u8
+    }
+    let start = label.iter().position(|&b| !is_space(b)).unwrap_or(label.len());
+    let end = label
+        .iter()
+        .rposition(|&b| !is_space(b))
+        .map_or(start, |p| p + 1);
+    &label[start..end]
+}
+
+#[inline]
+fn is_iso_2022_kr_label(label: &[u8]) -> bool {
+    let trimmed = trim_label(label);
+    trimmed.eq_ignore_ascii_case(b"iso-2022-kr") || trimmed.eq_ignore_ascii_case(b"csiso2022kr")
+}
+
+#[inline]
+fn is_iso_2022_cn_label(label: &[u8]) -> bool {
+    trim_label(label).eq_ignore_ascii_case(b"iso-2022-cn")
+}
+
 #[inline]
 fn utf7_ascii_up_to(bytes: &[u8]) -> usize {
     for (i, &byte) in bytes.into_iter().enumerate() {
@@ -378,8 +1043,19 @@ fn utf7_base64_decode(bytes: &[u8], string: &mut String) -> bool {
     let mut tail = bytes;
     let mut had_errors = false;
     loop {
-        let last = tail.len() <= 80;
-        let len = base64::decode_config_slice(tail, base64::STANDARD_NO_PAD, &mut buf[..]).unwrap();
+        let chunk_len = tail.len().min(80);
+        let last = chunk_len == tail.len();
+        let len = match base64::decode_config_slice(
+            &tail[..chunk_len],
+            base64::STANDARD_NO_PAD,
+            &mut buf[..],
+        ) {
+            Ok(len) => len,
+            Err(_) => {
+                string.push_str("\u{FFFD}");
+                return true;
+            }
+        };
         let mut total_read = 0;
         loop {
             let (result, read, err) = decoder.decode_to_string(&buf[total_read..len], string, last);
@@ -399,7 +1075,7 @@ fn utf7_base64_decode(bytes: &[u8], string: &mut String) -> bool {
                 }
             }
         }
-        tail = &tail[80..];
+        tail = &tail[chunk_len..];
     }
 }
 
@@ -421,7 +1097,7 @@ fn decode_utf7<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
         tail = &tail[1..];
         if first == b'+' {
             let up_to = utf7_base64_up_to(tail);
-            had_errors |= utf7_base64_decode(tail, &mut out);
+            had_errors |= utf7_base64_decode(&tail[..up_to], &mut out);
             if up_to == tail.len() {
                 return (Cow::Owned(out), had_errors);
             }
@@ -438,6 +1114,10 @@ fn decode_utf7<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
                     had_errors = true;
                     out.push_str("\u{FFFD}");
                 }
+            } else if tail[up_to] == b'-' {
+                // The `-` that ended the base64 run is just a shift
+                // terminator and isn't part of the decoded text.
+                tail = &tail[up_to + 1..];
             } else {
                 tail = &tail[up_to..];
             }
@@ -454,52 +1134,773 @@ fn decode_utf7<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
     }
 }
 
-#[derive(PartialEq, Debug, Copy, Clone, Hash)]
-enum VariantCharset {
-    Utf7,
-    Encoding(&'static Encoding),
+// `sub` must be a subslice of `base` (i.e. obtained from `base` by slicing),
+// as is always the case for the `tail` variables threaded through the
+// decoders below.
+#[inline]
+fn offset_of(base: &[u8], sub: &[u8]) -> usize {
+    (sub.as_ptr() as usize) - (base.as_ptr() as usize)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_for_label() {
-        assert_eq!(Charset::for_label(b"  uTf-7\t "), Some(UTF_7));
-        assert_eq!(
-            Charset::for_label(b"  uTf-8\t "),
-            Some(Charset::for_encoding(encoding_rs::UTF_8))
-        );
-        assert_eq!(
-            Charset::for_label(b"  iSo-8859-1\t "),
-            Some(Charset::for_encoding(encoding_rs::WINDOWS_1252))
-        );
-        assert_eq!(
-            Charset::for_label(b"  gb2312\t "),
-            Some(Charset::for_encoding(encoding_rs::GB18030))
-        );
-        assert_eq!(
-            Charset::for_label(b"  ISO-2022-KR\t "),
-            Some(Charset::for_encoding(encoding_rs::REPLACEMENT))
-        );
+// Applies `mode` to a malformed byte sequence found at `offset` in the
+// original input: pushes a replacement onto `out` and sets `*had_errors`,
+// or (for `ErrorMode::Strict`) aborts with the offset.
+#[inline]
+fn apply_error_mode(
+    mode: ErrorMode,
+    malformed: &[u8],
+    offset: usize,
+    out: &mut String,
+    had_errors: &mut bool,
+) -> Result<(), usize> {
+    match mode {
+        ErrorMode::Replace => out.push_str("\u{FFFD}"),
+        ErrorMode::Strict => return Err(offset),
+        ErrorMode::Custom(f) => out.push_str(&f(malformed)),
+    }
+    *had_errors = true;
+    Ok(())
+}
 
-        assert_eq!(Charset::for_label(b"u"), None);
-        assert_eq!(Charset::for_label(b"ut"), None);
-        assert_eq!(Charset::for_label(b"utf"), None);
-        assert_eq!(Charset::for_label(b"utf-"), None);
+#[inline(never)]
+fn decode_utf7_with_mode<'a>(
+    bytes: &'a [u8],
+    mode: ErrorMode,
+) -> Result<(Cow<'a, str>, bool), usize> {
+    if let ErrorMode::Replace = mode {
+        return Ok(decode_utf7(bytes));
+    }
+    let up_to = utf7_ascii_up_to(bytes);
+    if up_to == bytes.len() {
+        let s: &str = unsafe { std::str::from_utf8_unchecked(bytes) };
+        return Ok((Cow::Borrowed(s), false));
     }
+    let mut had_errors = false;
+    let mut out = String::with_capacity(bytes.len() * 3);
+    out.push_str(unsafe { std::str::from_utf8_unchecked(&bytes[..up_to]) });
 
-    #[test]
-    fn test_for_label_no_replacement() {
-        assert_eq!(
-            Charset::for_label_no_replacement(b"  uTf-7\t "),
-            Some(UTF_7)
-        );
-        assert_eq!(
-            Charset::for_label_no_replacement(b"  uTf-8\t "),
-            Some(Charset::for_encoding(encoding_rs::UTF_8))
-        );
+    let mut tail = &bytes[up_to..];
+    loop {
+        // `tail[0]` is now either a plus sign or non-ASCII
+        let offset = offset_of(bytes, tail);
+        let first = tail[0];
+        tail = &tail[1..];
+        if first == b'+' {
+            let up_to = utf7_base64_up_to(tail);
+            had_errors |= utf7_base64_decode_with_mode(
+                &tail[..up_to],
+                &mut out,
+                mode,
+                offset_of(bytes, tail),
+            )?;
+            if up_to == tail.len() {
+                return Ok((Cow::Owned(out), had_errors));
+            }
+            if up_to == 0 {
+                if tail[up_to] == b'-' {
+                    // There was no base64 data between
+                    // plus and minus, so we had the sequence
+                    // meaning the plus sign itself.
+                    out.push_str("+");
+                    tail = &tail[up_to + 1..];
+                } else {
+                    // Plus sign didn't start a base64 run and also
+                    // wasn't followed by a minus.
+                    apply_error_mode(
+                        mode,
+                        &bytes[offset..offset + 1],
+                        offset,
+                        &mut out,
+                        &mut had_errors,
+                    )?;
+                }
+            } else if tail[up_to] == b'-' {
+                // The `-` that ended the base64 run is just a shift
+                // terminator and isn't part of the decoded text.
+                tail = &tail[up_to + 1..];
+            } else {
+                tail = &tail[up_to..];
+            }
+        } else {
+            apply_error_mode(mode, &bytes[offset..offset + 1], offset, &mut out, &mut had_errors)?;
+        }
+        let up_to = utf7_ascii_up_to(tail);
+        out.push_str(unsafe { std::str::from_utf8_unchecked(&tail[..up_to]) });
+        if up_to == tail.len() {
+            return Ok((Cow::Owned(out), had_errors));
+        }
+        tail = &tail[up_to..];
+    }
+}
+
+// Mirrors `utf7_base64_decode`, but aborts (for `ErrorMode::Strict`) or
+// substitutes via `ErrorMode::Custom` on invalid base64 instead of always
+// substituting the REPLACEMENT CHARACTER. `offset` is the position of `bytes[0]` in the original input, for
+// reporting in `Err`.
+#[inline]
+fn utf7_base64_decode_with_mode(
+    bytes: &[u8],
+    string: &mut String,
+    mode: ErrorMode,
+    offset: usize,
+) -> Result<bool, usize> {
+    let mut decoder = UTF_16BE.new_decoder_without_bom_handling();
+    let mut buf = [0u8; 60];
+    let mut tail = bytes;
+    let mut pos = offset;
+    let mut had_errors = false;
+    loop {
+        let chunk_len = tail.len().min(80);
+        let last = chunk_len == tail.len();
+        let len = match base64::decode_config_slice(
+            &tail[..chunk_len],
+            base64::STANDARD_NO_PAD,
+            &mut buf[..],
+        ) {
+            Ok(len) => len,
+            Err(_) => {
+                apply_error_mode(mode, &tail[..chunk_len], pos, string, &mut had_errors)?;
+                return Ok(had_errors);
+            }
+        };
+        let mut total_read = 0;
+        loop {
+            let (result, read, err) = decoder.decode_to_string(&buf[total_read..len], string, last);
+            total_read += read;
+            had_errors |= err;
+            match result {
+                CoderResult::InputEmpty => {
+                    if last {
+                        return Ok(had_errors);
+                    }
+                    break;
+                }
+                CoderResult::OutputFull => {
+                    let left = len - total_read;
+                    let needed = decoder.max_utf8_buffer_length(left).unwrap();
+                    string.reserve(needed);
+                }
+            }
+        }
+        tail = &tail[chunk_len..];
+        pos += chunk_len;
+    }
+}
+
+// Like `Encoding::decode_with_bom_removal`/`decode_without_bom_handling`,
+// but wraps the low-level `Decoder::decode_to_string_without_replacement`
+// loop so `mode` can be honored instead of always substituting the
+// REPLACEMENT CHARACTER.
+fn decode_encoding_with_mode<'a>(
+    encoding: &'static Encoding,
+    bytes: &'a [u8],
+    mode: ErrorMode,
+    with_bom_removal: bool,
+) -> Result<(Cow<'a, str>, bool), usize> {
+    if let ErrorMode::Replace = mode {
+        return Ok(if with_bom_removal {
+            encoding.decode_with_bom_removal(bytes)
+        } else {
+            encoding.decode_without_bom_handling(bytes)
+        });
+    }
+    let mut decoder = if with_bom_removal {
+        encoding.new_decoder_with_bom_removal()
+    } else {
+        encoding.new_decoder_without_bom_handling()
+    };
+    let mut out = String::with_capacity(bytes.len());
+    let mut had_errors = false;
+    let mut total_read = 0usize;
+    loop {
+        let (result, read) =
+            decoder.decode_to_string_without_replacement(&bytes[total_read..], &mut out, true);
+        total_read += read;
+        match result {
+            DecoderResult::InputEmpty => return Ok((Cow::Owned(out), had_errors)),
+            DecoderResult::OutputFull => out.reserve(bytes.len()),
+            DecoderResult::Malformed(bad_len, extra) => {
+                let malformed_end = total_read - extra as usize;
+                let malformed_start = malformed_end - bad_len as usize;
+                if let ErrorMode::Strict = mode {
+                    return Err(malformed_start);
+                }
+                had_errors = true;
+                if let ErrorMode::Custom(f) = mode {
+                    out.push_str(&f(&bytes[malformed_start..malformed_end]));
+                }
+            }
+        }
+    }
+}
+
+// The IMAP modified-base64 alphabet (RFC 3501 section 5.1.3): the same as
+// standard base64 except `,` takes the place of `/`, and padding is never
+// used.
+const MUTF7_BASE64: base64::Config = base64::Config::new(base64::CharacterSet::ImapMutf7, false);
+
+#[inline]
+fn mutf7_ascii_up_to(bytes: &[u8]) -> usize {
+    for (i, &byte) in bytes.into_iter().enumerate() {
+        if byte == b'&' || byte >= 0x80 {
+            return i;
+        }
+    }
+    bytes.len()
+}
+
+#[inline]
+fn mutf7_base64_up_to(bytes: &[u8]) -> usize {
+    for (i, &byte) in bytes.into_iter().enumerate() {
+        match byte {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'+' | b',' => {}
+            _ => {
+                return i;
+            }
+        }
+    }
+    bytes.len()
+}
+
+#[inline]
+fn mutf7_base64_decode(bytes: &[u8], string: &mut String) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    let mut decoder = UTF_16BE.new_decoder_without_bom_handling();
+    let mut buf = [0u8; 60];
+    let mut tail = bytes;
+    let mut had_errors = false;
+    loop {
+        let chunk_len = std::cmp::min(tail.len(), 80);
+        let last = chunk_len == tail.len();
+        let len = match base64::decode_config_slice(&tail[..chunk_len], MUTF7_BASE64, &mut buf[..])
+        {
+            Ok(len) => len,
+            Err(_) => {
+                had_errors = true;
+                string.push_str("\u{FFFD}");
+                return had_errors;
+            }
+        };
+        let mut total_read = 0;
+        loop {
+            let (result, read, err) = decoder.decode_to_string(&buf[total_read..len], string, last);
+            total_read += read;
+            had_errors |= err;
+            match result {
+                CoderResult::InputEmpty => {
+                    if last {
+                        return had_errors;
+                    }
+                    break;
+                }
+                CoderResult::OutputFull => {
+                    let left = len - total_read;
+                    let needed = decoder.max_utf8_buffer_length(left).unwrap();
+                    string.reserve(needed);
+                }
+            }
+        }
+        tail = &tail[chunk_len..];
+    }
+}
+
+/// Decodes IMAP modified UTF-7 ([RFC 3501][1] section 5.1.3) to `Cow<'a, str>`,
+/// with malformed sequences replaced with the REPLACEMENT CHARACTER.
+///
+/// Unlike [RFC 2152][2] UTF-7 (see [`UTF_7`]), the shift character is `&`
+/// instead of `+` (so `&-` encodes a literal ampersand), the 64th
+/// modified-base64 character is `,` instead of `/`, and base64 runs are
+/// never padded with `=`.
+///
+/// The second item in the returned pair indicates whether there were
+/// malformed sequences (that were replaced with the REPLACEMENT CHARACTER).
+///
+/// [1]: https://tools.ietf.org/html/rfc3501#section-5.1.3
+/// [2]: https://tools.ietf.org/html/rfc2152
+#[inline(never)]
+pub fn decode_modified_utf7<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
+    let up_to = mutf7_ascii_up_to(bytes);
+    if up_to == bytes.len() {
+        let s: &str = unsafe { std::str::from_utf8_unchecked(bytes) };
+        return (Cow::Borrowed(s), false);
+    }
+    let mut had_errors = false;
+    let mut out = String::with_capacity(bytes.len() * 3);
+    out.push_str(unsafe { std::str::from_utf8_unchecked(&bytes[..up_to]) });
+
+    let mut tail = &bytes[up_to..];
+    loop {
+        // `tail[0]` is now either an ampersand or a non-ASCII byte.
+        let first = tail[0];
+        tail = &tail[1..];
+        if first == b'&' {
+            let up_to = mutf7_base64_up_to(tail);
+            if up_to == 0 {
+                if tail.first() == Some(&b'-') {
+                    // `&-` encodes a literal ampersand.
+                    out.push('&');
+                    tail = &tail[1..];
+                } else {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                }
+            } else {
+                had_errors |= mutf7_base64_decode(&tail[..up_to], &mut out);
+                tail = &tail[up_to..];
+                if tail.first() == Some(&b'-') {
+                    tail = &tail[1..];
+                } else {
+                    // A shift run not terminated by `-` is ill-formed,
+                    // whether it ran off the end of the input or stopped at
+                    // some other non-base64 byte. The base64 content already
+                    // decoded is kept and followed by a REPLACEMENT
+                    // CHARACTER.
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                }
+            }
+        } else {
+            had_errors = true;
+            out.push_str("\u{FFFD}");
+        }
+        if tail.is_empty() {
+            return (Cow::Owned(out), had_errors);
+        }
+        let up_to = mutf7_ascii_up_to(tail);
+        out.push_str(unsafe { std::str::from_utf8_unchecked(&tail[..up_to]) });
+        if up_to == tail.len() {
+            return (Cow::Owned(out), had_errors);
+        }
+        tail = &tail[up_to..];
+    }
+}
+
+/// Encodes a `&str` as IMAP modified UTF-7 ([RFC 3501][1] section 5.1.3).
+///
+/// This is the one exception to this crate's decode-only stance: callers
+/// constructing IMAP commands need to turn a mailbox name into the wire
+/// format, and this crate already owns the UTF-7 family of codecs.
+///
+/// [1]: https://tools.ietf.org/html/rfc3501#section-5.1.3
+pub fn encode_modified_utf7(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut units = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '&' {
+            out.push_str("&-");
+        } else if c as u32 >= 0x20 && c as u32 <= 0x7E {
+            out.push(c);
+        } else {
+            units.clear();
+            let mut buf = [0u16; 2];
+            units.extend_from_slice(c.encode_utf16(&mut buf));
+            while let Some(&next) = chars.peek() {
+                if next == '&' || (next as u32 >= 0x20 && next as u32 <= 0x7E) {
+                    break;
+                }
+                chars.next();
+                units.extend_from_slice(next.encode_utf16(&mut buf));
+            }
+            let mut bytes = Vec::with_capacity(units.len() * 2);
+            for unit in &units {
+                bytes.push((*unit >> 8) as u8);
+                bytes.push((*unit & 0xFF) as u8);
+            }
+            out.push('&');
+            out.push_str(&base64::encode_config(&bytes, MUTF7_BASE64));
+            out.push('-');
+        }
+    }
+    out
+}
+
+#[inline]
+fn hz_ascii_up_to(bytes: &[u8]) -> usize {
+    for (i, &byte) in bytes.into_iter().enumerate() {
+        if byte == b'~' || byte >= 0x80 {
+            return i;
+        }
+    }
+    bytes.len()
+}
+
+#[inline]
+fn gb2312_decode(bytes: &[u8], string: &mut String) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    let mut decoder = GB18030.new_decoder_without_bom_handling();
+    let mut had_errors = false;
+    let mut total_read = 0;
+    loop {
+        let (result, read, err) = decoder.decode_to_string(&bytes[total_read..], string, true);
+        total_read += read;
+        had_errors |= err;
+        match result {
+            CoderResult::InputEmpty => return had_errors,
+            CoderResult::OutputFull => {
+                let left = bytes.len() - total_read;
+                let needed = decoder.max_utf8_buffer_length(left).unwrap();
+                string.reserve(needed);
+            }
+        }
+    }
+}
+
+// Decodes a run of GB-2312 mode bytes starting right after the `~{` that
+// entered GB mode. Returns the remaining bytes (starting after the `~}`
+// that ended the run, or the end of input if the run was unterminated)
+// together with whether any malformed sequences were encountered.
+#[inline(never)]
+fn decode_hz_gb<'a>(bytes: &'a [u8], string: &mut String) -> (&'a [u8], bool) {
+    let mut had_errors = false;
+    let mut euc = Vec::with_capacity(bytes.len());
+    let mut tail = bytes;
+    loop {
+        if tail.is_empty() {
+            had_errors |= gb2312_decode(&euc, string);
+            return (tail, had_errors);
+        }
+        if tail[0] == b'~' && tail.len() >= 2 && tail[1] == b'}' {
+            had_errors |= gb2312_decode(&euc, string);
+            return (&tail[2..], had_errors);
+        }
+        if tail.len() < 2 || tail[0] < 0x21 || tail[0] > 0x7E || tail[1] < 0x21 || tail[1] > 0x7E {
+            gb2312_decode(&euc, string);
+            euc.clear();
+            had_errors = true;
+            string.push_str("\u{FFFD}");
+            tail = &tail[1..];
+            continue;
+        }
+        euc.push(tail[0] | 0x80);
+        euc.push(tail[1] | 0x80);
+        tail = &tail[2..];
+    }
+}
+
+#[inline(never)]
+fn decode_hz<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
+    let up_to = hz_ascii_up_to(bytes);
+    if up_to == bytes.len() {
+        let s: &str = unsafe { std::str::from_utf8_unchecked(bytes) };
+        return (Cow::Borrowed(s), false);
+    }
+    let mut had_errors = false;
+    let mut out = String::with_capacity(bytes.len() * 3);
+    out.push_str(unsafe { std::str::from_utf8_unchecked(&bytes[..up_to]) });
+
+    let mut tail = &bytes[up_to..];
+    loop {
+        // `tail[0]` is now either a tilde or a non-ASCII byte.
+        let first = tail[0];
+        tail = &tail[1..];
+        if first == b'~' {
+            match tail.first() {
+                None => {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    return (Cow::Owned(out), had_errors);
+                }
+                Some(&b'{') => {
+                    let (gb_tail, err) = decode_hz_gb(&tail[1..], &mut out);
+                    had_errors |= err;
+                    tail = gb_tail;
+                }
+                Some(&b'~') => {
+                    out.push('~');
+                    tail = &tail[1..];
+                }
+                Some(&b'\n') => {
+                    // Line-continuation: dropped.
+                    tail = &tail[1..];
+                }
+                Some(&b'}') => {
+                    // Already in ASCII mode; a redundant shift-back is accepted.
+                    tail = &tail[1..];
+                }
+                Some(_) => {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    tail = &tail[1..];
+                }
+            }
+        } else {
+            had_errors = true;
+            out.push_str("\u{FFFD}");
+        }
+        let up_to = hz_ascii_up_to(tail);
+        out.push_str(unsafe { std::str::from_utf8_unchecked(&tail[..up_to]) });
+        if up_to == tail.len() {
+            return (Cow::Owned(out), had_errors);
+        }
+        tail = &tail[up_to..];
+    }
+}
+
+#[inline]
+fn iso2022_ascii_up_to(bytes: &[u8]) -> usize {
+    for (i, &byte) in bytes.into_iter().enumerate() {
+        if byte == 0x1Bu8 || byte == 0x0Eu8 || byte == 0x0Fu8 || byte >= 0x80 {
+            return i;
+        }
+    }
+    bytes.len()
+}
+
+#[inline]
+fn euc_kr_decode(bytes: &[u8], string: &mut String) -> bool {
+    let mut decoder = EUC_KR.new_decoder_without_bom_handling();
+    let mut had_errors = false;
+    let mut total_read = 0;
+    loop {
+        let (result, read, err) = decoder.decode_to_string(&bytes[total_read..], string, true);
+        total_read += read;
+        had_errors |= err;
+        match result {
+            CoderResult::InputEmpty => return had_errors,
+            CoderResult::OutputFull => {
+                let left = bytes.len() - total_read;
+                let needed = decoder.max_utf8_buffer_length(left).unwrap();
+                string.reserve(needed);
+            }
+        }
+    }
+}
+
+// Implements the classic ISO-2022 `SO`/`SI`/designator model: `ESC $ ) C`
+// designates KSC 5601 to G1, `SO` shifts into G1 and `SI` shifts back to
+// ASCII. Bytes in G1 are 7-bit byte pairs that become EUC-KR once the high
+// bit is set on each byte.
+#[inline(never)]
+fn decode_iso2022kr<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
+    let up_to = iso2022_ascii_up_to(bytes);
+    if up_to == bytes.len() {
+        let s: &str = unsafe { std::str::from_utf8_unchecked(bytes) };
+        return (Cow::Borrowed(s), false);
+    }
+    let mut had_errors = false;
+    let mut out = String::with_capacity(bytes.len() * 3);
+    out.push_str(unsafe { std::str::from_utf8_unchecked(&bytes[..up_to]) });
+
+    let mut designated = false;
+    let mut shifted = false;
+    let mut tail = &bytes[up_to..];
+    loop {
+        if tail.is_empty() {
+            return (Cow::Owned(out), had_errors);
+        }
+        match tail[0] {
+            0x1Bu8 => {
+                if tail.len() >= 4 && &tail[1..4] == b"$)C" {
+                    designated = true;
+                    tail = &tail[4..];
+                } else {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    tail = &tail[1..];
+                }
+            }
+            0x0Eu8 => {
+                if designated {
+                    shifted = true;
+                } else {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                }
+                tail = &tail[1..];
+            }
+            0x0Fu8 => {
+                shifted = false;
+                tail = &tail[1..];
+            }
+            _ if shifted => {
+                if tail.len() < 2 || tail[0] < 0x21 || tail[0] > 0x7E || tail[1] < 0x21 || tail[1] > 0x7E
+                {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    tail = &tail[1..];
+                } else {
+                    let euc = [tail[0] | 0x80, tail[1] | 0x80];
+                    had_errors |= euc_kr_decode(&euc, &mut out);
+                    tail = &tail[2..];
+                }
+            }
+            _ if tail[0] >= 0x80 => {
+                had_errors = true;
+                out.push_str("\u{FFFD}");
+                tail = &tail[1..];
+            }
+            _ => {
+                let up_to = iso2022_ascii_up_to(tail);
+                out.push_str(unsafe { std::str::from_utf8_unchecked(&tail[..up_to]) });
+                tail = &tail[up_to..];
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum Iso2022CnG1 {
+    Undesignated,
+    Gb2312,
+    Cns1,
+}
+
+// `ESC $ ) A` designates GB 2312 and `ESC $ ) G` designates CNS 11643 plane 1
+// to G1; `SO`/`SI` shift in and out of it the same way as ISO-2022-KR. Plane
+// 1 is decoded the same way as GB 2312 (high-bit-setting into the GB18030
+// decoder), which is an approximation `encoding_rs` makes possible but isn't
+// exact. `ESC $ * H` designates CNS 11643 plane 2 to G2, entered with the
+// `SS2` sequence `ESC N`; `encoding_rs` has no decoder for plane 2, so those
+// bytes always become U+FFFD.
+#[inline(never)]
+fn decode_iso2022cn<'a>(bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
+    let up_to = iso2022_ascii_up_to(bytes);
+    if up_to == bytes.len() {
+        let s: &str = unsafe { std::str::from_utf8_unchecked(bytes) };
+        return (Cow::Borrowed(s), false);
+    }
+    let mut had_errors = false;
+    let mut out = String::with_capacity(bytes.len() * 3);
+    out.push_str(unsafe { std::str::from_utf8_unchecked(&bytes[..up_to]) });
+
+    let mut g1 = Iso2022CnG1::Undesignated;
+    let mut g2_designated = false;
+    let mut shifted = false;
+    let mut tail = &bytes[up_to..];
+    loop {
+        if tail.is_empty() {
+            return (Cow::Owned(out), had_errors);
+        }
+        match tail[0] {
+            0x1Bu8 => {
+                if tail.len() >= 4 && &tail[1..4] == b"$)A" {
+                    g1 = Iso2022CnG1::Gb2312;
+                    tail = &tail[4..];
+                } else if tail.len() >= 4 && &tail[1..4] == b"$)G" {
+                    g1 = Iso2022CnG1::Cns1;
+                    tail = &tail[4..];
+                } else if tail.len() >= 4 && &tail[1..4] == b"$*H" {
+                    g2_designated = true;
+                    tail = &tail[4..];
+                } else if tail.len() >= 2 && tail[1] == b'N' {
+                    tail = &tail[2..];
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    if g2_designated
+                        && tail.len() >= 2
+                        && tail[0] >= 0x21
+                        && tail[0] <= 0x7E
+                        && tail[1] >= 0x21
+                        && tail[1] <= 0x7E
+                    {
+                        tail = &tail[2..];
+                    } else if !tail.is_empty() {
+                        tail = &tail[1..];
+                    }
+                } else {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    tail = &tail[1..];
+                }
+            }
+            0x0Eu8 => {
+                if g1 != Iso2022CnG1::Undesignated {
+                    shifted = true;
+                } else {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                }
+                tail = &tail[1..];
+            }
+            0x0Fu8 => {
+                shifted = false;
+                tail = &tail[1..];
+            }
+            _ if shifted && g1 != Iso2022CnG1::Undesignated => {
+                if tail.len() < 2 || tail[0] < 0x21 || tail[0] > 0x7E || tail[1] < 0x21 || tail[1] > 0x7E
+                {
+                    had_errors = true;
+                    out.push_str("\u{FFFD}");
+                    tail = &tail[1..];
+                } else {
+                    let euc = [tail[0] | 0x80, tail[1] | 0x80];
+                    had_errors |= gb2312_decode(&euc, &mut out);
+                    tail = &tail[2..];
+                }
+            }
+            _ if tail[0] >= 0x80 => {
+                had_errors = true;
+                out.push_str("\u{FFFD}");
+                tail = &tail[1..];
+            }
+            _ => {
+                let up_to = iso2022_ascii_up_to(tail);
+                out.push_str(unsafe { std::str::from_utf8_unchecked(&tail[..up_to]) });
+                tail = &tail[up_to..];
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone, Hash)]
+enum VariantCharset {
+    Utf7,
+    ModifiedUtf7,
+    Hz,
+    Iso2022Kr,
+    Iso2022Cn,
+    Encoding(&'static Encoding),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_label() {
+        assert_eq!(Charset::for_label(b"  uTf-7\t "), Some(UTF_7));
+        assert_eq!(
+            Charset::for_label(b"  uTf-8\t "),
+            Some(Charset::for_encoding(encoding_rs::UTF_8))
+        );
+        assert_eq!(
+            Charset::for_label(b"  iSo-8859-1\t "),
+            Some(Charset::for_encoding(encoding_rs::WINDOWS_1252))
+        );
+        assert_eq!(
+            Charset::for_label(b"  gb2312\t "),
+            Some(Charset::for_encoding(encoding_rs::GB18030))
+        );
+        assert_eq!(
+            Charset::for_label(b"  ISO-2022-KR\t "),
+            Some(ISO_2022_KR)
+        );
+        assert_eq!(Charset::for_label(b"  csiso2022kr\t "), Some(ISO_2022_KR));
+        assert_eq!(Charset::for_label(b"  ISO-2022-CN\t "), Some(ISO_2022_CN));
+        assert_eq!(Charset::for_label(b"  hZ-Gb-2312\t "), Some(HZ_GB_2312));
+        assert_eq!(Charset::for_label(b"  Hz\t "), Some(HZ_GB_2312));
+
+        assert_eq!(Charset::for_label(b"u"), None);
+        assert_eq!(Charset::for_label(b"ut"), None);
+        assert_eq!(Charset::for_label(b"utf"), None);
+        assert_eq!(Charset::for_label(b"utf-"), None);
+    }
+
+    #[test]
+    fn test_for_label_no_replacement() {
+        assert_eq!(
+            Charset::for_label_no_replacement(b"  uTf-7\t "),
+            Some(UTF_7)
+        );
+        assert_eq!(
+            Charset::for_label_no_replacement(b"  uTf-8\t "),
+            Some(Charset::for_encoding(encoding_rs::UTF_8))
+        );
         assert_eq!(
             Charset::for_label_no_replacement(b"  iSo-8859-1\t "),
             Some(Charset::for_encoding(encoding_rs::WINDOWS_1252))
@@ -508,7 +1909,19 @@ mod tests {
             Charset::for_label_no_replacement(b"  Gb2312\t "),
             Some(Charset::for_encoding(encoding_rs::GB18030))
         );
-        assert_eq!(Charset::for_label_no_replacement(b"  ISO-2022-KR\t "), None);
+        assert_eq!(
+            Charset::for_label_no_replacement(b"  ISO-2022-KR\t "),
+            Some(ISO_2022_KR)
+        );
+        assert_eq!(
+            Charset::for_label_no_replacement(b"  ISO-2022-CN\t "),
+            Some(ISO_2022_CN)
+        );
+        assert_eq!(
+            Charset::for_label_no_replacement(b"  hZ-Gb-2312\t "),
+            Some(HZ_GB_2312)
+        );
+        assert_eq!(Charset::for_label_no_replacement(b"  Hz\t "), Some(HZ_GB_2312));
 
         assert_eq!(Charset::for_label_no_replacement(b"u"), None);
         assert_eq!(Charset::for_label_no_replacement(b"ut"), None);
@@ -524,6 +1937,169 @@ mod tests {
             Charset::for_label(b"  Gb2312\t ").unwrap().name(),
             "gb18030"
         );
+        assert_eq!(
+            Charset::for_label(b"  hz\t ").unwrap().name(),
+            "HZ-GB-2312"
+        );
+    }
+
+    #[test]
+    fn test_decode_hz() {
+        assert_eq!(
+            HZ_GB_2312.decode_without_bom_handling(b"Hi ~{VP~}!").0,
+            "Hi 中!"
+        );
+        assert_eq!(
+            HZ_GB_2312.decode_without_bom_handling(b"a~~b").0,
+            "a~b"
+        );
+        assert_eq!(
+            HZ_GB_2312.decode_without_bom_handling(b"a~\nb").0,
+            "ab"
+        );
+        let (cow, had_errors) = HZ_GB_2312.decode_without_bom_handling(b"a~xb");
+        assert_eq!(cow, "a\u{FFFD}b");
+        assert!(had_errors);
+    }
+
+    #[test]
+    fn test_decode_iso2022kr() {
+        let (cow, had_errors) =
+            ISO_2022_KR.decode_without_bom_handling(b"Hi \x1b$)C\x0eGQ\x0f!");
+        assert_eq!(cow, "Hi 한!");
+        assert!(!had_errors);
+
+        // `SO` before the designator is ill-formed.
+        let (_, had_errors) = ISO_2022_KR.decode_without_bom_handling(b"\x0eGQ");
+        assert!(had_errors);
+    }
+
+    #[test]
+    fn test_decode_iso2022cn() {
+        let (cow, had_errors) =
+            ISO_2022_CN.decode_without_bom_handling(b"Hi \x1b$)A\x0eVP\x0f!");
+        assert_eq!(cow, "Hi 中!");
+        assert!(!had_errors);
+
+        // CNS 11643 plane 2 has no `encoding_rs` representation.
+        let (cow, had_errors) =
+            ISO_2022_CN.decode_without_bom_handling(b"\x1b$*H\x1bNAB");
+        assert_eq!(cow, "\u{FFFD}");
+        assert!(had_errors);
+    }
+
+    #[test]
+    fn test_modified_utf7_roundtrip() {
+        assert_eq!(encode_modified_utf7("a&b"), "a&-b");
+        assert_eq!(decode_modified_utf7(b"a&-b").0, "a&b");
+
+        assert_eq!(encode_modified_utf7("Hi \u{263A}!"), "Hi &Jjo-!");
+        assert_eq!(decode_modified_utf7(b"Hi &Jjo-!").0, "Hi \u{263A}!");
+
+        let (cow, had_errors) = decode_modified_utf7(b"&Jjo");
+        assert_eq!(cow, "\u{263A}\u{FFFD}");
+        assert!(had_errors);
+    }
+
+    #[test]
+    fn test_streaming_utf7_decoder() {
+        // Feed "Hi +Jjo-!" one byte at a time and make sure the incremental
+        // decoder produces the same result as the one-shot `decode_utf7`,
+        // regardless of how the input happens to be chunked.
+        let input = b"Hi +Jjo-!";
+        let mut decoder = UTF_7.new_decoder_without_bom_handling();
+        let mut out = String::new();
+        let mut had_errors = false;
+        for (i, byte) in input.iter().enumerate() {
+            let last = i == input.len() - 1;
+            let (result, consumed, errors) = decoder.decode_to_string(&[*byte], &mut out, last);
+            assert_eq!(result, CoderResult::InputEmpty);
+            assert_eq!(consumed, 1);
+            had_errors |= errors;
+        }
+        assert_eq!(out, "Hi \u{263A}!");
+        assert!(!had_errors);
+
+        // A lone `+` at the very end of the stream, with nothing after it to
+        // show whether it starts a shift, is flushed as U+FFFD when `last`
+        // is true.
+        let mut decoder = UTF_7.new_decoder_without_bom_handling();
+        let mut out = String::new();
+        let (_, _, had_errors) = decoder.decode_to_string(b"+", &mut out, true);
+        assert_eq!(out, "\u{FFFD}");
+        assert!(had_errors);
+    }
+
+    #[test]
+    fn test_decode_reader() {
+        let mut reader = DecodeReader::new(&b"Hi +Jjo-!"[..], UTF_7, false);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "Hi \u{263A}!");
+
+        // A UTF-8 BOM overrides the fallback charset when `honor_bom` is set.
+        let mut reader = DecodeReader::new(&b"\xEF\xBB\xBFHi!"[..], UTF_7, true);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "Hi!");
+
+        // No BOM present: falls back to the given charset.
+        let mut reader = DecodeReader::new(&b"+Jjo-"[..], UTF_7, true);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "\u{263A}");
+    }
+
+    #[test]
+    fn test_decode_with_mode() {
+        let utf8 = Charset::for_encoding(encoding_rs::UTF_8);
+        let input = b"a\xFFb";
+
+        // `Replace` behaves exactly like the non-`_with_mode` methods.
+        let (cow, had_errors) = utf8
+            .decode_without_bom_handling_with_mode(input, ErrorMode::Replace)
+            .unwrap();
+        assert_eq!(cow, utf8.decode_without_bom_handling(input).0);
+        assert!(had_errors);
+
+        // `Strict` reports the offset of the first malformed sequence
+        // instead of producing output.
+        let offset = utf8
+            .decode_without_bom_handling_with_mode(input, ErrorMode::Strict)
+            .unwrap_err();
+        assert_eq!(offset, 1);
+
+        // `Custom` substitutes the caller's replacement for each malformed
+        // sequence.
+        fn escape(bytes: &[u8]) -> Cow<str> {
+            Cow::Owned(bytes.iter().map(|b| format!("&#x{:X};", b)).collect::<String>())
+        }
+        let (cow, had_errors) = utf8
+            .decode_without_bom_handling_with_mode(input, ErrorMode::Custom(escape))
+            .unwrap();
+        assert_eq!(cow, "a&#xFF;b");
+        assert!(had_errors);
+
+        // UTF-7 honors `mode` too.
+        let offset = UTF_7
+            .decode_without_bom_handling_with_mode(b"a\xFF", ErrorMode::Strict)
+            .unwrap_err();
+        assert_eq!(offset, 1);
+
+        // A well-formed `+...-` shift run followed by more text isn't
+        // malformed at all, regardless of `mode`.
+        let utf7_input = b"a+AGEAYgBj-b";
+        let (cow, had_errors) = UTF_7
+            .decode_without_bom_handling_with_mode(utf7_input, ErrorMode::Strict)
+            .unwrap();
+        assert_eq!(cow, "aabcb");
+        assert!(!had_errors);
+
+        let (cow, had_errors) = UTF_7
+            .decode_without_bom_handling_with_mode(utf7_input, ErrorMode::Custom(escape))
+            .unwrap();
+        assert_eq!(cow, "aabcb");
+        assert!(!had_errors);
     }
 
 }
\ No newline at end of file